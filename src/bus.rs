@@ -1,4 +1,8 @@
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
 use usb_device::{Result, UsbError};
 use usb_device::bus::{UsbBusWrapper, PollResult};
 use usb_device::endpoint::{EndpointDirection, EndpointType};
@@ -12,11 +16,24 @@ use stm32f103xx_hal::gpio::{self, gpioa};
 use regs::{NUM_ENDPOINTS, PacketMemory, EpReg, EndpointStatus, calculate_count_rx};
 use utils::SyncWrapper;
 
+const EVT_CTR: u8 = 1 << 0;
+const EVT_RESET: u8 = 1 << 1;
+const EVT_SUSP: u8 = 1 << 2;
+const EVT_WKUP: u8 = 1 << 3;
+
 #[derive(Default)]
 struct EndpointRecord {
     ep_type: Option<EndpointType>,
     out_valid: bool,
     in_valid: bool,
+    double_buffered: bool,
+    // Number of buffers currently holding a packet not yet consumed (0-2): received packets not
+    // yet read for OUT, written packets not yet transmitted for IN. Only used when
+    // `double_buffered` is set.
+    buf_pending: Cell<u8>,
+    // Set when a `write()`/`read()` finds both (for isochronous, the only) buffers still full or
+    // empty, i.e. a frame was missed. Read and cleared via `UsbBus::iso_frame_error`.
+    iso_error: Cell<bool>,
 }
 
 struct Reset {
@@ -24,6 +41,10 @@ struct Reset {
     pin: RefCell<gpioa::PA12<gpio::Output<gpio::PushPull>>>,
 }
 
+struct VbusDetect {
+    pin: gpioa::PA9<gpio::Input<gpio::Floating>>,
+}
+
 /// USB peripheral driver for STM32F103 microcontrollers.
 pub struct UsbBus {
     regs: SyncWrapper<USB>,
@@ -31,6 +52,13 @@ pub struct UsbBus {
     max_endpoint: usize,
     endpoints: [EndpointRecord; NUM_ENDPOINTS],
     reset: FreezableRefCell<Option<Reset>>,
+    vbus: FreezableRefCell<Option<VbusDetect>>,
+    vbus_present: Cell<bool>,
+    double_buffered_bulk: bool,
+    pending_events: AtomicU8,
+    waker: FreezableRefCell<Option<Waker>>,
+    irq_enabled: Cell<bool>,
+    lpm_enabled: Cell<bool>,
 }
 
 impl UsbBus {
@@ -51,9 +79,44 @@ impl UsbBus {
             max_endpoint: 0,
             endpoints: Default::default(),
             reset: FreezableRefCell::default(),
+            vbus: FreezableRefCell::default(),
+            vbus_present: Cell::new(false),
+            double_buffered_bulk: false,
+            pending_events: AtomicU8::new(0),
+            waker: FreezableRefCell::default(),
+            irq_enabled: Cell::new(false),
+            lpm_enabled: Cell::new(false),
         })
     }
 
+    /// Records whether the device supports USB 2.0 LPM (L1 sleep); the value is returned by
+    /// `lpm_enabled()`.
+    ///
+    /// The F103's USB peripheral has no LPM token detection hardware (no L1 request flag, no
+    /// BESL/bLinkState latch) the way later parts like the STM32L0/L4 USBFS do, so `poll()` has
+    /// no register-level signal to act on and cannot ACK or transition in/out of L1 itself. This
+    /// driver does not put the flag into any descriptor and does not act on it otherwise.
+    pub fn set_lpm_enabled(&self, enabled: bool) {
+        self.lpm_enabled.set(enabled);
+    }
+
+    /// Returns whether the device currently advertises USB 2.0 LPM support, as set by
+    /// `set_lpm_enabled()`.
+    pub fn lpm_enabled(&self) -> bool {
+        self.lpm_enabled.get()
+    }
+
+    /// Enables double-buffering for all bulk endpoints, exploiting the peripheral's two
+    /// buffer-descriptor slots per endpoint so firmware can fill the idle buffer while the host
+    /// drains the other one, roughly doubling sustained bulk throughput.
+    ///
+    /// Must be called before the endpoints are allocated (i.e. before `UsbDeviceBuilder::build`),
+    /// and only takes effect for bulk endpoints that use a single, unshared endpoint number for
+    /// their direction.
+    pub fn enable_double_buffered_bulk(&mut self) {
+        self.double_buffered_bulk = true;
+    }
+
     /// Gets an `UsbBusResetter` which can be used to force a USB reset and re-enumeration from the
     /// device side.
     ///
@@ -69,6 +132,142 @@ impl UsbBus {
         });
     }
 
+    /// Enables VBUS detection on the given pin, which must be wired to the VBUS rail through a
+    /// voltage divider.
+    ///
+    /// Once enabled, `poll()` will power the peripheral down (and report a `Suspend` result) when
+    /// VBUS is removed, and power it back up (reporting a `Resume` result) when it is replugged,
+    /// instead of leaving the peripheral hanging in a half-enumerated state.
+    pub fn enable_vbus_detection<M>(&mut self, crh: &mut gpioa::CRH, pa9: gpioa::PA9<M>) {
+        let pin = pa9.into_floating_input(crh);
+
+        self.vbus_present.set(pin.is_high());
+
+        *self.vbus.borrow_mut() = Some(VbusDetect { pin });
+    }
+
+    /// Returns whether the isochronous endpoint at `ep_addr` missed a frame since the last call,
+    /// i.e. a `write()` found both buffers still full (overrun) or a `read()` found neither
+    /// buffer ready (underrun), clearing the flag. Callers should fill silence for the frame in
+    /// either case. Always returns `false` for non-isochronous endpoints.
+    pub fn iso_frame_error(&self, ep_addr: u8) -> bool {
+        let index = (ep_addr & !0x80) as usize;
+
+        if index >= NUM_ENDPOINTS {
+            return false;
+        }
+
+        let record = &self.endpoints[index];
+
+        if record.ep_type != Some(EndpointType::Isochronous) {
+            return false;
+        }
+
+        record.iso_error.replace(false)
+    }
+
+    /// Signals remote wakeup, driving the K-state onto the bus so a suspended host notices this
+    /// device wants to resume (e.g. a HID keyboard waking the PC).
+    ///
+    /// Returns `UsbError::Unsupported` if the peripheral is not suspended. Whether the host has
+    /// enabled remote wakeup for this device is not checked here.
+    pub fn remote_wakeup(&self, clocks: &rcc::Clocks) -> Result<()> {
+        interrupt::free(|_| {
+            if !self.regs.cntr.read().fsusp().bit_is_set() {
+                return Err(UsbError::Unsupported);
+            }
+
+            self.regs.cntr.modify(|_, w| w.resume().set_bit());
+
+            // USB spec mandates driving the K-state for 1-15 ms.
+            delay(clocks.sysclk().0 / 100);
+
+            self.regs.cntr.modify(|_, w| w
+                .resume().clear_bit()
+                .fsusp().clear_bit()
+                .lpmode().clear_bit());
+
+            Ok(())
+        })
+    }
+
+    /// Enables the peripheral's interrupt sources (`CTRM`, `RESETM`, `SUSPM`, `WKUPM`) so that
+    /// `on_interrupt()` is invoked from the `USB_LP_CAN_RX0` ISR instead of requiring a
+    /// busy-polling loop.
+    ///
+    /// The caller is still responsible for unmasking the interrupt in the NVIC.
+    pub fn enable_interrupts(&self) {
+        interrupt::free(|_| {
+            self.regs.cntr.modify(|_, w| w
+                .ctrm().set_bit()
+                .resetm().set_bit()
+                .suspm().set_bit()
+                .wkupm().set_bit());
+        });
+
+        self.irq_enabled.set(true);
+    }
+
+    /// Entry point to be called from the `USB_LP_CAN_RX0` interrupt handler once
+    /// `enable_interrupts()` has been used.
+    ///
+    /// Records which events are pending so `poll()` can drain them without re-reading hardware,
+    /// and wakes a task blocked in `poll_wait()`, if any.
+    pub fn on_interrupt(&self) {
+        let istr = self.regs.istr.read();
+
+        let mut events = 0u8;
+
+        if istr.ctr().bit_is_set() {
+            events |= EVT_CTR;
+
+            // CTR stays set in hardware until software clears the per-endpoint CTR_RX/CTR_TX
+            // bits, which only happens in `read()`/`write()` running in task context. Mask CTRM
+            // here so the ISR doesn't re-fire continuously before the woken task gets a chance to
+            // drain it; `poll()` re-enables it once it has.
+            self.regs.cntr.modify(|_, w| w.ctrm().clear_bit());
+        }
+
+        if istr.reset().bit_is_set() { events |= EVT_RESET; }
+        if istr.susp().bit_is_set() { events |= EVT_SUSP; }
+        if istr.wkup().bit_is_set() { events |= EVT_WKUP; }
+
+        if events == 0 {
+            return;
+        }
+
+        self.pending_events.fetch_or(events, Ordering::SeqCst);
+
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    /// Returns a future that resolves the next time `on_interrupt()` observes bus activity,
+    /// letting an async executor `await` USB events instead of polling in a hot loop. Call
+    /// `poll()` after it resolves to find out what happened.
+    ///
+    /// Requires `enable_interrupts()` to have been called and the NVIC interrupt to be unmasked.
+    pub fn poll_wait(&self) -> PollWait {
+        PollWait { bus: self }
+    }
+
+    /// Powers up the analog transceiver and brings the macrocell out of forced reset: clears
+    /// `PDWN`, waits out the chip-specific startup delay, programs `BTABLE`, then clears `FRES`
+    /// and `ISTR`. While `FRES` is set the whole USB macrocell is held in reset with no SIE
+    /// activity, so every step here must run before the peripheral can do anything at all.
+    fn power_up(&self) {
+        self.regs.cntr.modify(|_, w| w.pdwn().clear_bit());
+
+        // There is a chip specific startup delay. For STM32F103xx it's 1µs and this should wait for
+        // at least that long.
+        delay(72);
+
+        self.regs.btable.modify(|_, w| unsafe { w.btable().bits(0) });
+        self.regs.cntr.modify(|_, w| w.fres().clear_bit());
+        self.regs.istr.modify(|_, w| unsafe { w.bits(0) });
+    }
+
     fn ep_regs(&self) -> &'static [EpReg; NUM_ENDPOINTS] {
         return unsafe { &*(&self.regs.ep0r as *const usb::EP0R as *const EpReg as *const [EpReg; NUM_ENDPOINTS]) };
     }
@@ -99,26 +298,69 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 Some(_) => { },
             };
 
+            // Isochronous endpoints are always hardware double-buffered (they have no
+            // STALL/NAK state to fall back on); bulk endpoints are only double-buffered when
+            // opted into via `enable_double_buffered_bulk`.
+            let double_buffered = ep_type == EndpointType::Isochronous
+                || (self.double_buffered_bulk && ep_type == EndpointType::Bulk);
+
             match ep_dir {
                 EndpointDirection::Out if !ep.out_valid => {
                     let (out_size, bits) = calculate_count_rx(max_packet_size as usize)?;
-
-                    let addr_rx = self.packet_mem.alloc(out_size)?;
                     let bd = &self.packet_mem.descrs()[index];
 
-                    bd.addr_rx.set(addr_rx);
-                    bd.count_rx.set(bits as usize);
+                    // A double-buffered endpoint uses both buffer descriptors of its number, so
+                    // the other direction cannot share that number.
+                    if ep.double_buffered {
+                        index += 1;
+                        continue;
+                    }
+
+                    if double_buffered && !ep.in_valid {
+                        let addr0 = self.packet_mem.alloc(out_size)?;
+                        let addr1 = self.packet_mem.alloc(out_size)?;
+
+                        bd.addr_rx.set(addr0);
+                        bd.count_rx.set(bits as usize);
+                        bd.addr_tx.set(addr1);
+                        bd.count_tx.set(bits as usize);
+
+                        ep.double_buffered = true;
+                    } else {
+                        let addr_rx = self.packet_mem.alloc(out_size)?;
+
+                        bd.addr_rx.set(addr_rx);
+                        bd.count_rx.set(bits as usize);
+                    }
 
                     ep.out_valid = true;
 
                     break;
                 },
                 EndpointDirection::In if !ep.in_valid => {
-                    let addr_tx = self.packet_mem.alloc(max_packet_size as usize)?;
                     let bd = &self.packet_mem.descrs()[index];
 
-                    bd.addr_tx.set(addr_tx);
-                    bd.count_tx.set(0);
+                    if ep.double_buffered {
+                        index += 1;
+                        continue;
+                    }
+
+                    if double_buffered && !ep.out_valid {
+                        let addr0 = self.packet_mem.alloc(max_packet_size as usize)?;
+                        let addr1 = self.packet_mem.alloc(max_packet_size as usize)?;
+
+                        bd.addr_tx.set(addr0);
+                        bd.count_tx.set(0);
+                        bd.addr_rx.set(addr1);
+                        bd.count_rx.set(0);
+
+                        ep.double_buffered = true;
+                    } else {
+                        let addr_tx = self.packet_mem.alloc(max_packet_size as usize)?;
+
+                        bd.addr_tx.set(addr_tx);
+                        bd.count_tx.set(0);
+                    }
 
                     ep.in_valid = true;
 
@@ -144,15 +386,14 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
         self.max_endpoint = max;
 
-        self.regs.cntr.modify(|_, w| w.pdwn().clear_bit());
-
-        // There is a chip specific startup delay. For STM32F103xx it's 1µs and this should wait for
-        // at least that long.
-        delay(72);
+        // If VBUS detection is enabled and VBUS isn't currently present, leave the peripheral
+        // powered down (FRES held, nothing programmed) instead of unconditionally presenting
+        // pull-ups with no cable attached; `poll()` runs this same power-up once VBUS arrives.
+        if self.vbus.borrow().is_some() && !self.vbus_present.get() {
+            return;
+        }
 
-        self.regs.btable.modify(|_, w| unsafe { w.btable().bits(0) });
-        self.regs.cntr.modify(|_, w| w.fres().clear_bit());
-        self.regs.istr.modify(|_, w| unsafe { w.bits(0) });
+        self.power_up();
     }
 
     fn reset(&self) {
@@ -163,14 +404,22 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 let reg = &self.ep_regs()[index];
 
                 if let Some(ep_type) = ep.ep_type {
-                    reg.configure(ep_type, index as u8);
+                    reg.configure(ep_type, index as u8, ep.double_buffered);
+
+                    ep.buf_pending.set(0);
 
                     if ep.out_valid {
                         reg.set_stat_rx(EndpointStatus::Valid);
                     }
 
                     if ep.in_valid {
-                        reg.set_stat_tx(EndpointStatus::Nak);
+                        // A double-buffered IN endpoint is left Valid permanently; the peripheral
+                        // alternates buffers on its own as long as software keeps one filled.
+                        reg.set_stat_tx(if ep.double_buffered {
+                            EndpointStatus::Valid
+                        } else {
+                            EndpointStatus::Nak
+                        });
                     }
                 }
             }
@@ -187,13 +436,44 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
     fn poll(&self) -> PollResult {
         interrupt::free(|_| {
-            let istr = self.regs.istr.read();
+            if let Some(ref vbus) = *self.vbus.borrow() {
+                let present = vbus.pin.is_high();
+
+                if present != self.vbus_present.get() {
+                    self.vbus_present.set(present);
+
+                    if present {
+                        // Bringing the macrocell out of forced reset, not just clearing `pdwn`,
+                        // is required before the SIE does anything at all.
+                        self.power_up();
+                    } else {
+                        self.regs.cntr.modify(|_, w| w.pdwn().set_bit());
+                    }
+
+                    return if present { PollResult::Resume } else { PollResult::Suspend };
+                }
+            }
+
+            // In interrupt-driven mode, `on_interrupt()` has already recorded which events are
+            // pending, so drain those instead of re-reading hardware in a spin loop. In plain
+            // busy-polling mode (no `enable_interrupts()`) this is always empty and we fall back
+            // to reading ISTR directly, exactly as before.
+            let pending = self.pending_events.swap(0, Ordering::SeqCst);
+
+            let (wkup, reset_evt, susp_evt, ctr_evt) = if pending != 0 {
+                (pending & EVT_WKUP != 0, pending & EVT_RESET != 0, pending & EVT_SUSP != 0,
+                    pending & EVT_CTR != 0)
+            } else {
+                let istr = self.regs.istr.read();
+
+                (istr.wkup().bit_is_set(), istr.reset().bit_is_set(), istr.susp().bit_is_set(),
+                    istr.ctr().bit_is_set())
+            };
 
-            if istr.wkup().bit_is_set() {
+            let result = if wkup {
                 self.regs.istr.modify(|_, w| w.wkup().clear_bit());
 
                 let fnr = self.regs.fnr.read();
-                let bits = (fnr.rxdp().bit_is_set() as u8) << 1 | (fnr.rxdm().bit_is_set() as u8);
 
                 match (fnr.rxdp().bit_is_set(), fnr.rxdm().bit_is_set()) {
                     (false, false) | (false, true) => {
@@ -204,22 +484,23 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                         PollResult::Suspend
                     }
                 }
-            } else if istr.reset().bit_is_set() {
+            } else if reset_evt {
                 self.regs.istr.modify(|_, w| w.reset().clear_bit());
 
                 PollResult::Reset
-            } else if istr.susp().bit_is_set() {
+            } else if susp_evt {
                 self.regs.istr.modify(|_, w| w.susp().clear_bit());
 
                 PollResult::Suspend
-            } else if istr.ctr().bit_is_set() {
+            } else if ctr_evt {
                 let mut ep_out = 0;
                 let mut ep_in_complete = 0;
                 let mut ep_setup = 0;
                 let mut bit = 1;
 
-                for reg in &self.ep_regs()[0..=self.max_endpoint] {
+                for (index, reg) in self.ep_regs()[0..=self.max_endpoint].iter().enumerate() {
                     let v = reg.read();
+                    let record = &self.endpoints[index];
 
                     if v.ctr_rx().bit_is_set() {
                         ep_out |= bit;
@@ -227,12 +508,21 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                         if v.setup().bit_is_set() {
                             ep_setup |= bit;
                         }
+
+                        if record.double_buffered {
+                            reg.clear_ctr_rx();
+                            record.buf_pending.set((record.buf_pending.get() + 1).min(2));
+                        }
                     }
 
                     if v.ctr_tx().bit_is_set() {
                         ep_in_complete |= bit;
 
                         reg.clear_ctr_tx();
+
+                        if record.double_buffered {
+                            record.buf_pending.set(record.buf_pending.get().saturating_sub(1));
+                        }
                     }
 
                     bit <<= 1;
@@ -241,7 +531,22 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 PollResult::Data { ep_out, ep_in_complete, ep_setup }
             } else {
                 PollResult::None
+            };
+
+            // Non-double-buffered endpoints leave their CTR_RX/CTR_TX bits set until the caller's
+            // `read()`/`write()` clears them after draining this `Data` result, so re-arming CTRM
+            // now would immediately re-trigger the ISR on that still-pending condition. Defer the
+            // re-arm to the next `poll()` call, by which point the caller has had a chance to
+            // drain; double-buffered endpoints already had their CTR bits cleared above, so they
+            // don't need the re-arm gated, but gating it uniformly is harmless.
+            if self.irq_enabled.get() {
+                if let PollResult::Data { .. } = result {
+                } else {
+                    self.regs.cntr.modify(|_, w| w.ctrm().set_bit());
+                }
             }
+
+            result
         })
     }
 
@@ -258,6 +563,32 @@ impl ::usb_device::bus::UsbBus for UsbBus {
             }
 
             let reg = &self.ep_regs()[ep as usize];
+            let record = &self.endpoints[ep as usize];
+            let bd = &self.packet_mem.descrs()[ep as usize];
+
+            if record.double_buffered {
+                if record.buf_pending.get() >= 2 {
+                    record.iso_error.set(true);
+                    return Err(UsbError::Busy);
+                }
+
+                // Fill whichever buffer software isn't currently pointed at. Per RM0008 §21.4.3,
+                // for a double-buffered IN endpoint DTOG_TX tracks which buffer the peripheral
+                // last used, while DTOG_RX is repurposed as the software-side SW_BUF flag; hand
+                // the filled buffer to the peripheral by toggling DTOG_RX, not DTOG_TX.
+                if reg.dtog_tx() {
+                    self.packet_mem.write(bd.addr_rx.get(), buf);
+                    bd.count_rx.set(buf.len());
+                } else {
+                    self.packet_mem.write(bd.addr_tx.get(), buf);
+                    bd.count_tx.set(buf.len());
+                }
+
+                reg.toggle_dtog_rx();
+                record.buf_pending.set(record.buf_pending.get() + 1);
+
+                return Ok(buf.len());
+            }
 
             match reg.read().stat_tx().bits().into() {
                 EndpointStatus::Valid => return Err(UsbError::Busy),
@@ -265,8 +596,6 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 _ => {},
             };
 
-            let bd = &self.packet_mem.descrs()[ep as usize];
-
             // TODO: validate len
 
             self.packet_mem.write(bd.addr_tx.get(), buf);
@@ -285,6 +614,37 @@ impl ::usb_device::bus::UsbBus for UsbBus {
             }
 
             let reg = &self.ep_regs()[ep as usize];
+            let record = &self.endpoints[ep as usize];
+            let bd = &self.packet_mem.descrs()[ep as usize];
+
+            if record.double_buffered {
+                if record.buf_pending.get() == 0 {
+                    record.iso_error.set(true);
+                    return Err(UsbError::NoData);
+                }
+
+                // Drain whichever buffer DTOG_RX currently selects. Per RM0008 §21.4.3, for a
+                // double-buffered OUT endpoint DTOG_RX tracks which buffer the peripheral last
+                // filled, while DTOG_TX is repurposed as the software-side SW_BUF flag; release
+                // the drained buffer back to the peripheral by toggling DTOG_TX, not DTOG_RX.
+                let (addr, count) = if reg.dtog_rx() {
+                    (bd.addr_tx.get(), bd.count_tx.get())
+                } else {
+                    (bd.addr_rx.get(), bd.count_rx.get())
+                };
+
+                let count = count & 0x3f;
+                if count > buf.len() {
+                    return Err(UsbError::BufferOverflow);
+                }
+
+                self.packet_mem.read(addr, &mut buf[..count]);
+
+                reg.toggle_dtog_tx();
+                record.buf_pending.set(record.buf_pending.get() - 1);
+
+                return Ok(count);
+            }
 
             let reg_v = reg.read();
 
@@ -298,8 +658,6 @@ impl ::usb_device::bus::UsbBus for UsbBus {
                 return Err(UsbError::NoData);
             }
 
-            let bd = &self.packet_mem.descrs()[ep as usize];
-
             let count = bd.count_rx.get() & 0x3f;
             if count > buf.len() {
                 return Err(UsbError::BufferOverflow);
@@ -316,6 +674,11 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
     fn stall(&self, ep: u8) {
         interrupt::free(|_| {
+            // Isochronous endpoints have no STALL state.
+            if self.endpoints[(ep & !0x80) as usize].ep_type == Some(EndpointType::Isochronous) {
+                return;
+            }
+
             if ep & 0x80 != 0 {
                 self.ep_regs()[(ep & !0x80) as usize].set_stat_tx(EndpointStatus::Stall);
             } else {
@@ -326,6 +689,10 @@ impl ::usb_device::bus::UsbBus for UsbBus {
 
     fn unstall(&self, ep: u8) {
         interrupt::free(|_| {
+            if self.endpoints[(ep & !0x80) as usize].ep_type == Some(EndpointType::Isochronous) {
+                return;
+            }
+
             let reg = &self.ep_regs()[(ep & !0x80) as usize];
 
             if ep & 0x80 != 0 {
@@ -375,3 +742,31 @@ impl ::usb_device::bus::UsbBus for UsbBus {
         })
     }
 }
+
+/// Future returned by `UsbBus::poll_wait()`. Resolves the next time the USB interrupt observes
+/// bus activity.
+pub struct PollWait<'a> {
+    bus: &'a UsbBus,
+}
+
+impl<'a> Future for PollWait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.bus.pending_events.load(Ordering::SeqCst) != 0 {
+            return Poll::Ready(());
+        }
+
+        interrupt::free(|_| {
+            *self.bus.waker.borrow_mut() = Some(cx.waker().clone());
+        });
+
+        // The interrupt may have fired, and found no waker to wake, between the check above and
+        // registering the waker, so check once more before committing to Pending.
+        if self.bus.pending_events.load(Ordering::SeqCst) != 0 {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,12 @@
+#![no_std]
+
+extern crate cortex_m;
+extern crate stm32f103xx;
+extern crate stm32f103xx_hal;
+extern crate usb_device;
+
+mod bus;
+mod regs;
+mod utils;
+
+pub use bus::{PollWait, UsbBus};
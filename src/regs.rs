@@ -0,0 +1,231 @@
+use core::cell::Cell;
+use core::slice;
+use usb_device::{Result, UsbError};
+use usb_device::endpoint::EndpointType;
+use stm32f103xx::usb;
+
+/// Number of endpoints supported by the peripheral (including EP0).
+pub const NUM_ENDPOINTS: usize = 8;
+
+const PMA_ADDR: usize = 0x4000_6000;
+const PMA_SIZE_WORDS: usize = 256;
+
+/// One row of the buffer descriptor table, mirroring the hardware's BTABLE layout.
+#[repr(C)]
+pub struct BufferDescriptor {
+    pub addr_tx: Cell<usize>,
+    pub count_tx: Cell<usize>,
+    pub addr_rx: Cell<usize>,
+    pub count_rx: Cell<usize>,
+}
+
+/// Bump allocator over the 512-byte dedicated USB packet memory, plus raw access to it.
+pub struct PacketMemory {
+    next_free_offset: Cell<usize>,
+}
+
+impl PacketMemory {
+    pub fn new() -> PacketMemory {
+        PacketMemory {
+            // The buffer descriptor table itself lives at the start of packet memory.
+            next_free_offset: Cell::new(NUM_ENDPOINTS * 8),
+        }
+    }
+
+    pub fn alloc(&self, size: usize) -> Result<usize> {
+        let size = (size + 1) & !1;
+        let offset = self.next_free_offset.get();
+
+        if offset + size > PMA_SIZE_WORDS * 2 {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        self.next_free_offset.set(offset + size);
+
+        Ok(offset)
+    }
+
+    pub fn descrs(&self) -> &'static [BufferDescriptor; NUM_ENDPOINTS] {
+        unsafe { &*(PMA_ADDR as *const [BufferDescriptor; NUM_ENDPOINTS]) }
+    }
+
+    fn word_ptr(&self, addr: usize) -> *mut u16 {
+        (PMA_ADDR + addr * 2) as *mut u16
+    }
+
+    pub fn write(&self, addr: usize, buf: &[u8]) {
+        let mut addr = addr;
+
+        for chunk in buf.chunks(2) {
+            let word = chunk[0] as u16 | (*chunk.get(1).unwrap_or(&0) as u16) << 8;
+
+            unsafe { self.word_ptr(addr).write_volatile(word) };
+
+            addr += 2;
+        }
+    }
+
+    pub fn read(&self, addr: usize, buf: &mut [u8]) {
+        let mut addr = addr;
+
+        for chunk in buf.chunks_mut(2) {
+            let word = unsafe { self.word_ptr(addr).read_volatile() };
+
+            chunk[0] = word as u8;
+            if let Some(b) = chunk.get_mut(1) {
+                *b = (word >> 8) as u8;
+            }
+
+            addr += 2;
+        }
+    }
+}
+
+/// Computes the `COUNT_RX` block-size/count bits for a given max packet size, returning the
+/// actual allocated buffer size in bytes alongside the register bits.
+pub fn calculate_count_rx(max_packet_size: usize) -> Result<(usize, u16)> {
+    if max_packet_size <= 62 {
+        let size = (max_packet_size + 1) & !1;
+        Ok((size, ((size / 2) << 10) as u16))
+    } else if max_packet_size <= 1024 {
+        let size = (max_packet_size + 31) & !31;
+        Ok((size, (0x8000 | (((size / 32) - 1) << 10)) as u16))
+    } else {
+        Err(UsbError::EndpointMemoryOverflow)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EndpointStatus {
+    Disabled = 0b00,
+    Stall = 0b01,
+    Nak = 0b10,
+    Valid = 0b11,
+}
+
+impl From<u8> for EndpointStatus {
+    fn from(v: u8) -> EndpointStatus {
+        match v & 0b11 {
+            0b00 => EndpointStatus::Disabled,
+            0b01 => EndpointStatus::Stall,
+            0b10 => EndpointStatus::Nak,
+            _ => EndpointStatus::Valid,
+        }
+    }
+}
+
+const EP_TYPE_BULK: u8 = 0b00;
+const EP_TYPE_CONTROL: u8 = 0b01;
+const EP_TYPE_ISO: u8 = 0b10;
+const EP_TYPE_INTERRUPT: u8 = 0b11;
+
+// STAT_TX/STAT_RX/DTOG_TX/DTOG_RX are "write 1 to toggle" - writing 0 leaves the bit unchanged.
+// CTR_TX/CTR_RX are "write 0 to clear" - writing 1 leaves the bit unchanged. EP_TYPE/EP_KIND/EA
+// are plain read/write. `EpReg` reinterprets any `EPnR` as an `EP0R` and drives it through that
+// SVD API, which does not model these semantics: every method below uses `write()` (toggle bits
+// written as 0) and writes 1 to each CTR bit it does not mean to clear.
+#[repr(transparent)]
+pub struct EpReg(usb::EP0R);
+
+impl EpReg {
+    pub fn read(&self) -> usb::ep0r::R {
+        self.0.read()
+    }
+
+    pub fn configure(&self, ep_type: EndpointType, index: u8, double_buffered: bool) {
+        let ep_type_bits = match ep_type {
+            EndpointType::Control => EP_TYPE_CONTROL,
+            EndpointType::Isochronous => EP_TYPE_ISO,
+            EndpointType::Bulk => EP_TYPE_BULK,
+            EndpointType::Interrupt => EP_TYPE_INTERRUPT,
+        };
+
+        // Isochronous endpoints are unconditionally hardware double-buffered; EP_KIND is reserved
+        // (must stay 0) for them. For bulk endpoints, EP_KIND is the DBL_BUF opt-in.
+        let ep_kind = double_buffered && ep_type != EndpointType::Isochronous;
+
+        self.0.write(|w| unsafe {
+            w.ep_type().bits(ep_type_bits).ea().bits(index).ep_kind().bit(ep_kind)
+        });
+    }
+
+    /// Returns which buffer (`false` = buffer 0, `true` = buffer 1) software should fill on the
+    /// next `write()` to a double-buffered endpoint. For a double-buffered IN endpoint this bit
+    /// tracks the buffer the peripheral last transmitted from (RM0008 §21.4.3); releasing a
+    /// filled buffer back to the peripheral is done by toggling `DTOG_RX` (the SW_BUF flag for
+    /// this direction), not this bit.
+    pub fn dtog_tx(&self) -> bool {
+        self.read().dtog_tx().bit_is_set()
+    }
+
+    /// Returns which buffer the peripheral is not currently pointing at, i.e. the one it just
+    /// finished filling for a double-buffered endpoint. For a double-buffered OUT endpoint this
+    /// bit tracks the buffer the peripheral last received into (RM0008 §21.4.3); releasing a
+    /// drained buffer back to the peripheral is done by toggling `DTOG_TX` (the SW_BUF flag for
+    /// this direction), not this bit.
+    pub fn dtog_rx(&self) -> bool {
+        self.read().dtog_rx().bit_is_set()
+    }
+
+    pub fn toggle_dtog_tx(&self) {
+        self.0.write(|w| unsafe {
+            w.ctr_rx().set_bit().ctr_tx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+                .dtog_tx().set_bit()
+        });
+    }
+
+    pub fn toggle_dtog_rx(&self) {
+        self.0.write(|w| unsafe {
+            w.ctr_rx().set_bit().ctr_tx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+                .dtog_rx().set_bit()
+        });
+    }
+
+    pub fn set_stat_tx(&self, status: EndpointStatus) {
+        let toggle = self.read().stat_tx().bits() ^ (status as u8);
+
+        self.0.write(|w| unsafe {
+            w.ctr_rx().set_bit().ctr_tx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+                .stat_tx().bits(toggle)
+        });
+    }
+
+    pub fn set_stat_rx(&self, status: EndpointStatus) {
+        let toggle = self.read().stat_rx().bits() ^ (status as u8);
+
+        self.0.write(|w| unsafe {
+            w.ctr_rx().set_bit().ctr_tx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+                .stat_rx().bits(toggle)
+        });
+    }
+
+    pub fn clear_ctr_rx(&self) {
+        self.0.write(|w| unsafe {
+            w.ctr_rx().clear_bit().ctr_tx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+        });
+    }
+
+    pub fn clear_ctr_tx(&self) {
+        self.0.write(|w| unsafe {
+            w.ctr_tx().clear_bit().ctr_rx().set_bit()
+                .ea().bits(self.read().ea().bits())
+                .ep_type().bits(self.read().ep_type().bits())
+                .ep_kind().bit(self.read().ep_kind().bit_is_set())
+        });
+    }
+}